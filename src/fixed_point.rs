@@ -0,0 +1,262 @@
+//! Deterministic fixed-precision LMSR math, behind the `fixed-point` feature.
+//!
+//! `f64` gives different rounding on different platforms, which is unacceptable for
+//! on-chain/consensus settings, so this mirrors `MarketMaker` on `rug::Float` at a
+//! caller-chosen precision. `exp`/`ln` are implemented from scratch (a fixed number of
+//! Taylor-series terms for `exp`, a fixed number of iterations over that `exp` for
+//! `ln`) rather than relying on a possibly-divergent system libm, so
+//! any two nodes evaluating the same shares perform the exact same operations and
+//! agree bit-for-bit.
+
+use rug::Float;
+
+// Number of Taylor-series terms used once an argument has been range-reduced to
+// exp's small-input regime. Fixed so every node does the same fixed amount of work.
+const EXP_SERIES_TERMS: u32 = 40;
+
+// Number of Halley iterations used to invert exp() when computing ln(). Halley's
+// method converges cubically here, so a fixed count comfortably reaches the
+// target precision regardless of the starting guess.
+const LN_NEWTON_ITERATIONS: u32 = 60;
+
+// Number of terms used by ln2(), which converges geometrically at ratio (1/9) per
+// term, so this comfortably exceeds any precision this module is used at.
+const LN2_SERIES_TERMS: u32 = 60;
+
+// ln(2) = 2 * atanh(1/3) = 2 * sum_n (1/3)^(2n+1) / (2n+1), computed entirely from
+// Float arithmetic (no f64 involved), so it's bit-identical on any two nodes.
+fn ln2(precision: u32) -> Float {
+    let x = Float::with_val(precision, 1) / Float::with_val(precision, 3);
+    let x_squared = Float::with_val(precision, &x * &x);
+
+    let mut term = x.clone();
+    let mut sum = Float::with_val(precision, 0);
+    for n in 0..LN2_SERIES_TERMS {
+        let denominator = Float::with_val(precision, 2 * n + 1);
+        sum += Float::with_val(precision, &term / &denominator);
+        term = Float::with_val(precision, &term * &x_squared);
+    }
+
+    Float::with_val(precision, 2) * sum
+}
+
+// Deterministic seed for ln_newton: repeatedly halve/double x (pure Float arithmetic,
+// no f64) until it lands in [1, 2), tracking the power of two k, then seed with
+// k * ln2. Unlike an f64-derived guess, every step here is exact Float arithmetic, so
+// the seed itself is bit-identical across platforms — not just the Newton refinement.
+fn ln_seed(x: &Float, precision: u32) -> Float {
+    let one = Float::with_val(precision, 1);
+    let two = Float::with_val(precision, 2);
+
+    let mut k: i64 = 0;
+    let mut reduced = x.clone();
+    while reduced >= two {
+        reduced /= 2;
+        k += 1;
+    }
+    while reduced < one {
+        reduced *= 2;
+        k -= 1;
+    }
+
+    Float::with_val(precision, k) * ln2(precision)
+}
+
+// exp(x) via scaling-and-squaring: reduce x to |x/2^k| <= 1 so the Taylor series
+// converges in a fixed number of terms, then undo the reduction by squaring k times.
+fn exp_taylor(x: &Float, precision: u32) -> Float {
+    let mut k = 0_u32;
+    let mut reduced = x.clone();
+    while reduced.clone().abs() > 1 {
+        reduced /= 2;
+        k += 1;
+    }
+
+    let mut term = Float::with_val(precision, 1);
+    let mut sum = Float::with_val(precision, 1);
+    for n in 1..=EXP_SERIES_TERMS {
+        term *= &reduced;
+        term /= n;
+        sum += term.clone();
+    }
+
+    for _ in 0..k {
+        sum = Float::with_val(precision, &sum * &sum);
+    }
+
+    sum
+}
+
+// ln(x) for x > 0, by Halley's method on f(y) = exp(y) - x, whose step is
+// 2 * (x - exp(y)) / (x + exp(y)). The starting guess only
+// needs to be in the right ballpark; the fixed number of iterations does the work of
+// reaching full precision deterministically.
+fn ln_newton(x: &Float, precision: u32) -> Float {
+    let mut y = ln_seed(x, precision);
+
+    for _ in 0..LN_NEWTON_ITERATIONS {
+        let e = exp_taylor(&y, precision);
+        let numerator = Float::with_val(precision, x - &e);
+        let denominator = Float::with_val(precision, x + &e);
+        y += Float::with_val(precision, 2) * numerator / denominator;
+    }
+
+    y
+}
+
+#[derive(Clone)]
+pub struct FixedMarketMaker {
+    pub b: Float,
+    pub outstanding_shares: Vec<Float>,
+    pub precision: u32,
+}
+
+impl FixedMarketMaker {
+    pub fn new(b: f64, num_outcomes: usize, precision: u32) -> FixedMarketMaker {
+        let b = Float::with_val(precision, b);
+        let outstanding_shares = vec![Float::with_val(precision, 0); num_outcomes];
+
+        FixedMarketMaker { b, outstanding_shares, precision }
+    }
+
+    // Mirrors MarketMaker::stabilized_terms: returns (m, sum) where m = max_i(q_i/b)
+    // and sum = sum_i(exp(q_i/b - m)), so every exponent stays <= 0.
+    fn stabilized_terms(&self) -> (Float, Float) {
+        let mut m = Float::with_val(self.precision, f64::NEG_INFINITY);
+        for q in &self.outstanding_shares {
+            let scaled = Float::with_val(self.precision, q / &self.b);
+            if scaled > m {
+                m = scaled;
+            }
+        }
+
+        let mut sum = Float::with_val(self.precision, 0);
+        for q in &self.outstanding_shares {
+            let scaled = Float::with_val(self.precision, q / &self.b - &m);
+            sum += exp_taylor(&scaled, self.precision);
+        }
+
+        (m, sum)
+    }
+
+    pub fn cost_fn(&self) -> Float {
+        let (m, sum) = self.stabilized_terms();
+
+        Float::with_val(self.precision, &self.b * (m + ln_newton(&sum, self.precision)))
+    }
+
+    pub fn price(&self, outcome_id: usize) -> Float {
+        let (m, sum) = self.stabilized_terms();
+        let scaled = Float::with_val(self.precision, &self.outstanding_shares[outcome_id] / &self.b - &m);
+
+        Float::with_val(self.precision, exp_taylor(&scaled, self.precision) / sum)
+    }
+
+    pub fn cost_to_trade(&self, outcome_id: usize, shares: f64) -> Float {
+        let mut new_market_maker = self.clone();
+        new_market_maker.outstanding_shares[outcome_id] += Float::with_val(self.precision, shares);
+
+        Float::with_val(self.precision, new_market_maker.cost_fn() - self.cost_fn())
+    }
+
+    // Generalized n-outcome version, mirroring MarketMaker::shares_to_set_price:
+    // delta = b * ln( (p / (1-p)) * sum_{i != k}(exp(q_i/b)) / exp(q_k/b) ).
+    pub fn shares_to_set_price(&self, outcome_id: usize, new_price: f64) -> Float {
+        let (m, sum) = self.stabilized_terms();
+        let scaled_k = Float::with_val(self.precision, &self.outstanding_shares[outcome_id] / &self.b - &m);
+        let exp_k = exp_taylor(&scaled_k, self.precision);
+        let sum_others = Float::with_val(self.precision, &sum - &exp_k);
+
+        let p = Float::with_val(self.precision, new_price);
+        let one_minus_p = Float::with_val(self.precision, 1 - &p);
+        let ratio = Float::with_val(self.precision, &p / one_minus_p);
+        let arg = Float::with_val(self.precision, ratio * sum_others / exp_k);
+
+        Float::with_val(self.precision, &self.b * ln_newton(&arg, self.precision))
+    }
+
+    pub fn trade(&mut self, outcome_id: usize, shares: f64) {
+        self.outstanding_shares[outcome_id] += Float::with_val(self.precision, shares);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarketMaker;
+
+    const PRECISION: u32 = 100;
+
+    fn assert_within_epsilon(x: f64, y: f64) {
+        assert!((x - y).abs() < 0.0001, "{} and {} aren't within epsilon", x, y);
+    }
+
+    #[test]
+    fn cost_fn_matches_f64_path() {
+        let market_maker = MarketMaker { b: 100.0, outstanding_shares: vec![10.0, 0.0] };
+        let mut fixed_market_maker = FixedMarketMaker::new(100.0, 2, PRECISION);
+        fixed_market_maker.trade(0, 10.0);
+
+        assert_within_epsilon(market_maker.cost_fn(), fixed_market_maker.cost_fn().to_f64());
+    }
+
+    #[test]
+    fn price_matches_f64_path() {
+        let market_maker = MarketMaker { b: 100.0, outstanding_shares: vec![44.0, 17.0] };
+        let mut fixed_market_maker = FixedMarketMaker::new(100.0, 2, PRECISION);
+        fixed_market_maker.trade(0, 44.0);
+        fixed_market_maker.trade(1, 17.0);
+
+        assert_within_epsilon(market_maker.price(0), fixed_market_maker.price(0).to_f64());
+        assert_within_epsilon(market_maker.price(1), fixed_market_maker.price(1).to_f64());
+    }
+
+    #[test]
+    fn shares_to_set_price_matches_f64_path() {
+        let market_maker = MarketMaker { b: 100.0, outstanding_shares: vec![40.0, 12.0] };
+        let mut fixed_market_maker = FixedMarketMaker::new(100.0, 2, PRECISION);
+        fixed_market_maker.trade(0, 40.0);
+        fixed_market_maker.trade(1, 12.0);
+
+        let target = 0.6;
+        let expected = market_maker.shares_to_set_price(1, target);
+        let result = fixed_market_maker.shares_to_set_price(1, target);
+
+        assert_within_epsilon(expected, result.to_f64());
+    }
+
+    #[test]
+    fn same_inputs_produce_byte_identical_floats() {
+        let mut a = FixedMarketMaker::new(100.0, 2, PRECISION);
+        let mut b = FixedMarketMaker::new(100.0, 2, PRECISION);
+        a.trade(0, 10.0);
+        b.trade(0, 10.0);
+
+        assert_eq!(a.cost_fn(), b.cost_fn());
+    }
+
+    // Pins ln_newton against a fixed reference value rather than comparing two
+    // same-platform runs against each other, which would pass trivially even if the
+    // seed were still derived from platform f64::ln.
+    #[test]
+    fn ln_newton_matches_pinned_reference_value() {
+        // ln(100), pinned as a literal reference value rather than derived at test time.
+        let expected = Float::with_val(PRECISION, 4.605170185988091_f64);
+        let x = Float::with_val(PRECISION, 100);
+        let result = ln_newton(&x, PRECISION);
+
+        let epsilon = Float::with_val(PRECISION, 1e-9);
+        assert!(
+            (result - expected).abs() < epsilon,
+            "ln_newton(100) didn't match the pinned reference value"
+        );
+    }
+
+    #[test]
+    fn ln_seed_is_computed_without_f64() {
+        // Regression guard for the seed itself: for x in [1, 2) the reduction loop in
+        // ln_seed shouldn't halve or double at all, so the seed is exactly 0 * ln2 = 0.
+        let x = Float::with_val(PRECISION, 1.5);
+        assert_eq!(ln_seed(&x, PRECISION), Float::with_val(PRECISION, 0));
+    }
+}
@@ -5,6 +5,14 @@ use std::collections::HashMap;
 
 use rug::ops::Pow;
 
+#[cfg(feature = "fixed-point")]
+mod fixed_point;
+#[cfg(feature = "fixed-point")]
+pub use fixed_point::FixedMarketMaker;
+
+mod order_book;
+pub use order_book::{FillBreakdown, Order, OrderBook, Side};
+
 #[derive(Clone)]
 pub struct MarketMaker {
     pub b: f64,
@@ -19,12 +27,19 @@ impl MarketMaker {
     }
 
     pub fn cost_fn(&self) -> f64 {
-        self.b * self.sum_of_exp().ln()
+        let (m, sum) = self.stabilized_terms();
+        self.b * (m + sum.ln())
     }
 
-    // Calculates exp(q1/b) for each outcome and sums
-    fn sum_of_exp(&self) -> f64 {
-        self.outstanding_shares.iter().fold(0_f64, |acc, q| acc + E.pow(q / self.b))
+    // Computes max_i(q_i / b) and sum_i(exp(q_i/b - m)) together, via the
+    // log-sum-exp trick. Subtracting the max before exponentiating keeps every
+    // exponent <= 0, so this stays finite even when q_i/b would otherwise
+    // overflow E.pow, and is exact when all shares are zero (m = 0).
+    fn stabilized_terms(&self) -> (f64, f64) {
+        let m = self.outstanding_shares.iter().fold(f64::NEG_INFINITY, |acc, q| acc.max(q / self.b));
+        let sum = self.outstanding_shares.iter().fold(0_f64, |acc, q| acc + E.pow(q / self.b - m));
+
+        (m, sum)
     }
 
     pub fn cost_to_trade(&self, outcome_id: usize, shares: f64) -> f64 {
@@ -35,17 +50,60 @@ impl MarketMaker {
     }
 
     pub fn price(&self, outcome_id: usize) -> f64 {
-        E.pow(self.outstanding_shares[outcome_id] / self.b) / self.sum_of_exp()
+        let (m, sum) = self.stabilized_terms();
+        E.pow(self.outstanding_shares[outcome_id] / self.b - m) / sum
     }
 
+    // Solves for the delta to add to outcome_id's shares, holding every other
+    // outcome's shares fixed, that sets price(outcome_id) to new_price:
+    //   delta = b * ln( (p / (1-p)) * sum_{i != k}(exp(q_i/b)) / exp(q_k/b) )
+    // Expressed via stabilized_terms so it stays correct (and finite) for any number
+    // of outcomes, not just two.
     pub fn shares_to_set_price(&self, outcome_id: usize, new_price: f64) -> f64 {
-        let current_price = self.price(outcome_id);
-        self.b * ((new_price / current_price).ln() - ((1.0 - new_price) / (1.0 - current_price)).ln())
+        let (m, sum) = self.stabilized_terms();
+        let exp_k = E.pow(self.outstanding_shares[outcome_id] / self.b - m);
+        let sum_of_others = sum - exp_k;
+
+        self.b * ((new_price / (1.0 - new_price)) * sum_of_others / exp_k).ln()
     }
 
     pub fn trade(&mut self, outcome_id: usize, shares: f64) {
         self.outstanding_shares[outcome_id] += shares;
     }
+
+    // Prices a single atomic trade that adds `delta` shares to every outcome in `buy`
+    // and removes `delta` shares from every outcome in `sell`, leaving `keep` untouched,
+    // by evaluating cost_fn once before and once after the combined move. `buy`, `sell`,
+    // and `keep` must partition 0..num_outcomes exactly; anything else is an error.
+    pub fn cost_to_trade_combination(&self, buy: &[usize], sell: &[usize], keep: &[usize], delta: f64) -> Result<f64, String> {
+        self.validate_partition(buy, sell, keep)?;
+
+        let mut new_market_maker = self.clone();
+        for &outcome_id in buy {
+            new_market_maker.outstanding_shares[outcome_id] += delta;
+        }
+        for &outcome_id in sell {
+            new_market_maker.outstanding_shares[outcome_id] -= delta;
+        }
+
+        Ok(new_market_maker.cost_fn() - self.cost_fn())
+    }
+
+    fn validate_partition(&self, buy: &[usize], sell: &[usize], keep: &[usize]) -> Result<(), String> {
+        let mut seen = vec![false; self.outstanding_shares.len()];
+        for &outcome_id in buy.iter().chain(sell).chain(keep) {
+            match seen.get_mut(outcome_id) {
+                None => return Err(format!("outcome {} is out of range", outcome_id)),
+                Some(true) => return Err(format!("outcome {} appears in more than one of buy/sell/keep", outcome_id)),
+                Some(seen) => *seen = true,
+            }
+        }
+        if seen.iter().any(|&s| !s) {
+            return Err("buy, sell, and keep must together cover every outcome".to_string());
+        }
+
+        Ok(())
+    }
 }
 
 pub struct Portfolio {
@@ -57,14 +115,113 @@ pub struct Market {
     pub market_maker: MarketMaker,
     pub portfolios: HashMap<String, Portfolio>,
     pub num_outcomes: usize,
+    pub resolved: bool,
+    // Fraction of 1 unit of collateral paid out per share of each outcome, set by
+    // resolve()/resolve_with_payout_vector(). None until the market is resolved.
+    pub payout_vector: Option<Vec<f64>>,
+    // Proportional fee charged on top of the LMSR cost of every trade, e.g. 0.01 for 1%.
+    pub fee_rate: f64,
+    // Total fees collected over the life of the market. Cumulative and never
+    // decremented; withdrawals are tracked separately in `withdrawn_fees`.
+    pub collected_fees: f64,
+    // Fees collected so far, broken down by the outcome that was traded.
+    pub fees_by_outcome: Vec<f64>,
+    // Lifetime amount each address has already withdrawn via withdraw_fees, so
+    // claimable_fees can return entitlement minus what's already been paid out instead
+    // of re-deriving a fresh claim from the (otherwise undiminished) collected_fees.
+    pub withdrawn_fees: HashMap<String, f64>,
+    // Resting limit orders, consulted by route_buy/route_sell before the curve.
+    pub order_book: OrderBook,
 }
 
 impl Market {
     pub fn new(b: f64, num_outcomes: usize) -> Market {
+        Market::new_with_fee_rate(b, num_outcomes, 0.0)
+    }
+
+    pub fn new_with_fee_rate(b: f64, num_outcomes: usize, fee_rate: f64) -> Market {
         let market_maker = MarketMaker::new(b, num_outcomes);
         let portfolios = HashMap::new();
 
-        Market { market_maker, portfolios, num_outcomes }
+        Market {
+            market_maker,
+            portfolios,
+            num_outcomes,
+            resolved: false,
+            payout_vector: None,
+            fee_rate,
+            collected_fees: 0.0,
+            fees_by_outcome: vec![0.0; num_outcomes],
+            withdrawn_fees: HashMap::new(),
+            order_book: OrderBook::new(num_outcomes),
+        }
+    }
+
+    // Posts a resting limit order directly, without attempting to match it against the
+    // curve first. `route_buy`/`route_sell` are the entry points for a market order
+    // that should match immediately where possible.
+    pub fn post_limit_order(&mut self, order: Order) {
+        if self.resolved || !self.portfolios.contains_key(&order.address) {
+            return;
+        }
+
+        self.order_book.post(order);
+    }
+
+    // Resolves the market to a single winning outcome, paying 1 unit of collateral per
+    // winning share on redeem.
+    pub fn resolve(&mut self, winning_outcome: usize) -> Result<(), String> {
+        if winning_outcome >= self.num_outcomes {
+            return Err(format!("winning outcome {} is out of range for {} outcomes", winning_outcome, self.num_outcomes));
+        }
+
+        let mut payout_vector = vec![0.0; self.num_outcomes];
+        payout_vector[winning_outcome] = 1.0;
+
+        self.resolve_with_payout_vector(payout_vector)
+    }
+
+    // Resolves the market with a scalar/fractional payout vector, so ranged markets can
+    // settle proportionally instead of all-or-nothing. `payout_vector` must have one
+    // entry per outcome and sum to 1.
+    pub fn resolve_with_payout_vector(&mut self, payout_vector: Vec<f64>) -> Result<(), String> {
+        if payout_vector.len() != self.num_outcomes {
+            return Err(format!("payout vector must have {} entries, got {}", self.num_outcomes, payout_vector.len()));
+        }
+        let total: f64 = payout_vector.iter().sum();
+        if (total - 1.0).abs() > 0.0001 {
+            return Err(format!("payout vector must sum to 1.0, got {}", total));
+        }
+
+        self.payout_vector = Some(payout_vector);
+        self.resolved = true;
+
+        Ok(())
+    }
+
+    // Pays out `address`'s position according to the resolved payout vector, plus any
+    // remaining collateral, then zeroes the position. Returns 0.0 if the market hasn't
+    // resolved yet or the address holds no portfolio.
+    pub fn redeem(&mut self, address: String) -> f64 {
+        let payout_vector = match &self.payout_vector {
+            None => return 0.0,
+            Some(payout_vector) => payout_vector.clone(),
+        };
+
+        match self.portfolios.get_mut(&address) {
+            None => 0.0,
+            Some(portfolio) => {
+                let winnings: f64 = portfolio.outcome_shares.iter().zip(payout_vector.iter())
+                    .map(|(shares, payout)| shares * payout)
+                    .sum();
+                let payout = winnings + portfolio.collateral;
+
+                portfolio.outcome_shares = vec![0.0; portfolio.outcome_shares.len()];
+                portfolio.collateral = 0.0;
+
+                payout
+            }
+        }
     }
 
     pub fn add_collateral(&mut self, address: String, amount: f64) {
@@ -77,14 +234,20 @@ impl Market {
     }
 
     pub fn trade(&mut self, address: String, outcome_id: usize, shares: f64) {
+        if self.resolved { return; }
+
         match self.portfolios.get_mut(&address) {
             None => return,
             Some(portfolio) => {
                 let cost = self.market_maker.cost_to_trade(outcome_id, shares);
-                if portfolio.collateral >= cost {
+                let fee = self.fee_rate * cost.abs();
+                if portfolio.collateral >= cost + fee {
                     portfolio.outcome_shares[outcome_id] += shares;
-                    portfolio.collateral -= cost;
+                    portfolio.collateral -= cost + fee;
                     self.market_maker.trade(outcome_id, shares);
+
+                    self.collected_fees += fee;
+                    self.fees_by_outcome[outcome_id] += fee;
                 } else {
                     return;
                 }
@@ -92,6 +255,39 @@ impl Market {
         }
     }
 
+    // Atomically buys `delta` shares of every outcome in `buy`, sells `delta` shares of
+    // every outcome in `sell`, and leaves `keep` untouched, charging a single combined
+    // cost. Lets a caller bet on e.g. "A or B vs. C" in one trade instead of legging
+    // into each outcome separately. The trade is only applied if collateral covers the
+    // whole combined cost, so it can never partially apply.
+    pub fn trade_combination(&mut self, address: String, buy: &[usize], sell: &[usize], keep: &[usize], delta: f64) -> Result<(), String> {
+        if self.resolved { return Err("market is resolved".to_string()); }
+
+        match self.portfolios.get_mut(&address) {
+            None => Ok(()),
+            Some(portfolio) => {
+                let cost = self.market_maker.cost_to_trade_combination(buy, sell, keep, delta)?;
+                if portfolio.collateral >= cost {
+                    for &outcome_id in buy {
+                        portfolio.outcome_shares[outcome_id] += delta;
+                    }
+                    for &outcome_id in sell {
+                        portfolio.outcome_shares[outcome_id] -= delta;
+                    }
+                    portfolio.collateral -= cost;
+
+                    for &outcome_id in buy {
+                        self.market_maker.trade(outcome_id, delta);
+                    }
+                    for &outcome_id in sell {
+                        self.market_maker.trade(outcome_id, -delta);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
     pub fn buy_with_max_price(&mut self, address: String, outcome_id: usize, shares: f64, max_price: f64) {
         if shares < 0.0 { return };
 
@@ -102,6 +298,40 @@ impl Market {
             self.trade(address, outcome_id, shares);
         }
     }
+
+    // Distributes collected_fees pro-rata to liquidity providers by their share of the
+    // market's total outstanding shares. This crate doesn't distinguish LPs from
+    // traders, so any portfolio holding outstanding shares is treated as an LP.
+    // Returns the address's lifetime entitlement minus what it's already withdrawn, so
+    // repeated calls (or calls after other addresses withdraw) don't double-pay.
+    pub fn claimable_fees(&self, address: &str) -> f64 {
+        let portfolio = match self.portfolios.get(address) {
+            None => return 0.0,
+            Some(portfolio) => portfolio,
+        };
+
+        let total_outstanding_shares: f64 = self.market_maker.outstanding_shares.iter().sum();
+        if total_outstanding_shares <= 0.0 {
+            return 0.0;
+        }
+
+        let portfolio_shares: f64 = portfolio.outcome_shares.iter().sum();
+        let entitled = self.collected_fees * (portfolio_shares / total_outstanding_shares);
+        let already_withdrawn = self.withdrawn_fees.get(address).copied().unwrap_or(0.0);
+
+        (entitled - already_withdrawn).max(0.0)
+    }
+
+    pub fn withdraw_fees(&mut self, address: String) -> f64 {
+        let claimable = self.claimable_fees(&address);
+        if claimable <= 0.0 {
+            return 0.0;
+        }
+
+        *self.withdrawn_fees.entry(address).or_insert(0.0) += claimable;
+
+        claimable
+    }
 }
 
 #[cfg(test)]
@@ -151,6 +381,36 @@ mod tests {
         unimplemented!();
     }
 
+    #[test]
+    fn cost_fn_stays_finite_with_large_shares() {
+        let b = 1_f64;
+        let outstanding_shares = vec!(5000_f64, 0_f64);
+
+        let naive = b * (E.pow(outstanding_shares[0] / b) + E.pow(outstanding_shares[1] / b)).ln();
+        assert!(naive.is_infinite(), "expected the naive computation to overflow to inf");
+
+        let market_maker = MarketMaker { b, outstanding_shares };
+        let result = market_maker.cost_fn();
+
+        assert!(result.is_finite());
+        assert_within_epsilon(result, 5000_f64);
+    }
+
+    #[test]
+    fn price_stays_finite_with_large_shares() {
+        let b = 1_f64;
+        let outstanding_shares = vec!(5000_f64, 0_f64);
+
+        let market_maker = MarketMaker { b, outstanding_shares };
+
+        let price_0 = market_maker.price(0);
+        let price_1 = market_maker.price(1);
+
+        assert!(price_0.is_finite() && price_1.is_finite());
+        assert_within_epsilon(price_0 + price_1, 1.0);
+        assert!(price_0 > price_1);
+    }
+
     #[test]
     fn cost_to_trade_works() {
         let market_maker = MarketMaker::new(100.0, 2);
@@ -208,6 +468,46 @@ mod tests {
         assert_within_epsilon(target, result);
     }
 
+    #[test]
+    fn shares_to_set_price_works_with_three_options() {
+        let b = 100_f64;
+        let outstanding_shares = vec!(40_f64, 12_f64, 30_f64);
+        let market_maker = MarketMaker { b, outstanding_shares };
+
+        let target = 0.5_f64;
+        let outcome_id = 2;
+
+        let shares_to_buy = market_maker.shares_to_set_price(outcome_id, target);
+
+        let mut outstanding_shares = market_maker.outstanding_shares.clone();
+        outstanding_shares[outcome_id] += shares_to_buy;
+        let market_maker = MarketMaker { b, outstanding_shares };
+
+        let result = market_maker.price(outcome_id);
+
+        assert_within_epsilon(target, result);
+    }
+
+    #[test]
+    fn shares_to_set_price_works_with_four_options() {
+        let b = 100_f64;
+        let outstanding_shares = vec!(40_f64, 12_f64, 30_f64, 5_f64);
+        let market_maker = MarketMaker { b, outstanding_shares };
+
+        let target = 0.1_f64;
+        let outcome_id = 0;
+
+        let shares_to_buy = market_maker.shares_to_set_price(outcome_id, target);
+
+        let mut outstanding_shares = market_maker.outstanding_shares.clone();
+        outstanding_shares[outcome_id] += shares_to_buy;
+        let market_maker = MarketMaker { b, outstanding_shares };
+
+        let result = market_maker.price(outcome_id);
+
+        assert_within_epsilon(target, result);
+    }
+
     #[test]
     fn trade_works_on_mm() {
         let mut market_maker = MarketMaker::new(100.0, 2);
@@ -274,6 +574,165 @@ mod tests {
         assert_within_epsilon(final_collateral, 4.0);
     }
 
+    #[test]
+    fn cost_to_trade_combination_matches_combined_cost_fn() {
+        let market_maker = MarketMaker::new(100.0, 3);
+
+        let result = market_maker.cost_to_trade_combination(&[0, 1], &[2], &[], 10.0).unwrap();
+
+        let mut expected_market_maker = market_maker.clone();
+        expected_market_maker.outstanding_shares = vec!(10.0, 10.0, -10.0);
+        let expected = expected_market_maker.cost_fn() - market_maker.cost_fn();
+
+        assert_within_epsilon(result, expected);
+    }
+
+    #[test]
+    fn cost_to_trade_combination_rejects_overlapping_sets() {
+        let market_maker = MarketMaker::new(100.0, 3);
+
+        let result = market_maker.cost_to_trade_combination(&[0, 1], &[1], &[2], 10.0);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn cost_to_trade_combination_rejects_incomplete_partition() {
+        let market_maker = MarketMaker::new(100.0, 3);
+
+        let result = market_maker.cost_to_trade_combination(&[0], &[1], &[], 10.0);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn market_allows_trading_a_combination() {
+        let mut market = Market::new(100.0, 3);
+        let address = "0x6891Ac4E2EF3dA9bc88C96fEDbC9eA4d6D88F768";
+
+        market.add_collateral(String::from(address), 100.0);
+        market.trade_combination(String::from(address), &[0, 1], &[2], &[], 5.0).unwrap();
+
+        let portfolio = &market.portfolios[&String::from(address)];
+        assert_eq!(portfolio.outcome_shares, vec!(5.0, 5.0, -5.0));
+        assert_eq!(market.market_maker.outstanding_shares, vec!(5.0, 5.0, -5.0));
+    }
+
+    #[test]
+    fn trade_charges_proportional_fee() {
+        let mut market = Market::new_with_fee_rate(100.0, 2, 0.1);
+        let address = "0x6891Ac4E2EF3dA9bc88C96fEDbC9eA4d6D88F768";
+        let shares = 10.0;
+
+        market.add_collateral(String::from(address), 20.0);
+        market.trade(String::from(address), 1, shares);
+
+        let cost = 5.124947_f64;
+        let fee = 0.1 * cost;
+
+        let portfolio = &market.portfolios[&String::from(address)];
+        assert_within_epsilon(portfolio.collateral, 20.0 - cost - fee);
+        assert_within_epsilon(market.collected_fees, fee);
+        assert_within_epsilon(market.fees_by_outcome[1], fee);
+    }
+
+    #[test]
+    fn claimable_fees_are_distributed_pro_rata_to_share_holders() {
+        let mut market = Market::new_with_fee_rate(100.0, 2, 0.1);
+        let alice = "alice";
+        let bob = "bob";
+
+        market.add_collateral(String::from(alice), 1000.0);
+        market.add_collateral(String::from(bob), 1000.0);
+
+        market.trade(String::from(alice), 0, 30.0);
+        market.trade(String::from(bob), 0, 10.0);
+
+        let total_fees = market.collected_fees;
+        let alice_claim = market.claimable_fees(alice);
+        let bob_claim = market.claimable_fees(bob);
+
+        assert_within_epsilon(alice_claim, total_fees * 0.75);
+        assert_within_epsilon(bob_claim, total_fees * 0.25);
+
+        let withdrawn = market.withdraw_fees(String::from(alice));
+        assert_within_epsilon(withdrawn, alice_claim);
+        // collected_fees is cumulative and unaffected by withdrawals; alice's
+        // entitlement is now fully paid out, so she has nothing left to claim.
+        assert_within_epsilon(market.collected_fees, total_fees);
+        assert_eq!(market.claimable_fees(alice), 0.0);
+
+        let withdrawn_again = market.withdraw_fees(String::from(alice));
+        assert_eq!(withdrawn_again, 0.0);
+
+        // bob's claim is untouched by alice's withdrawal.
+        assert_within_epsilon(market.claimable_fees(bob), bob_claim);
+    }
+
+    #[test]
+    fn resolve_and_redeem_pays_winning_shares_and_collateral() {
+        let mut market = Market::new(100.0, 2);
+        let address = "0x6891Ac4E2EF3dA9bc88C96fEDbC9eA4d6D88F768";
+
+        market.add_collateral(String::from(address), 100.0);
+        market.trade(String::from(address), 0, 10.0);
+
+        let remaining_collateral = market.portfolios[&String::from(address)].collateral;
+
+        market.resolve(0).unwrap();
+        let payout = market.redeem(String::from(address));
+
+        assert_within_epsilon(payout, 10.0 + remaining_collateral);
+        let portfolio = &market.portfolios[&String::from(address)];
+        assert_eq!(portfolio.outcome_shares, vec!(0.0, 0.0));
+        assert_eq!(portfolio.collateral, 0.0);
+    }
+
+    #[test]
+    fn resolve_with_payout_vector_splits_proportionally() {
+        let mut market = Market::new(100.0, 2);
+        let address = "0x6891Ac4E2EF3dA9bc88C96fEDbC9eA4d6D88F768";
+
+        market.add_collateral(String::from(address), 100.0);
+        market.trade(String::from(address), 0, 10.0);
+        market.trade(String::from(address), 1, 10.0);
+
+        let remaining_collateral = market.portfolios[&String::from(address)].collateral;
+
+        market.resolve_with_payout_vector(vec!(0.25, 0.75)).unwrap();
+        let payout = market.redeem(String::from(address));
+
+        assert_within_epsilon(payout, 10.0 * 0.25 + 10.0 * 0.75 + remaining_collateral);
+    }
+
+    #[test]
+    fn resolve_rejects_payout_vector_not_summing_to_one() {
+        let mut market = Market::new(100.0, 2);
+
+        assert!(market.resolve_with_payout_vector(vec!(0.25, 0.5)).is_err());
+    }
+
+    #[test]
+    fn resolve_rejects_out_of_range_winning_outcome() {
+        let mut market = Market::new(100.0, 2);
+
+        assert!(market.resolve(2).is_err());
+        assert_eq!(market.resolved, false);
+    }
+
+    #[test]
+    fn trade_is_rejected_after_resolution() {
+        let mut market = Market::new(100.0, 2);
+        let address = "0x6891Ac4E2EF3dA9bc88C96fEDbC9eA4d6D88F768";
+
+        market.add_collateral(String::from(address), 100.0);
+        market.resolve(0).unwrap();
+        market.trade(String::from(address), 0, 10.0);
+
+        let portfolio = &market.portfolios[&String::from(address)];
+        assert_eq!(portfolio.outcome_shares, vec!(0.0, 0.0));
+    }
+
     #[test]
     fn buy_with_max_price_works() {
         let mut market = Market::new(100.0, 2);
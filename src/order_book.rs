@@ -0,0 +1,340 @@
+//! Limit order book layered over the LMSR, with a router that fills against resting
+//! orders before falling back to the curve. Mirrors a hybrid AMM/order-book design:
+//! an incoming market order first takes any resting order that beats the curve's
+//! current marginal price, then walks the curve for whatever's left.
+
+use crate::Market;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(Clone, Debug)]
+pub struct Order {
+    pub address: String,
+    pub outcome_id: usize,
+    pub shares: f64,
+    pub limit_price: f64,
+    pub side: Side,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FillBreakdown {
+    pub book_shares: f64,
+    pub book_average_price: f64,
+    pub curve_shares: f64,
+    pub curve_average_price: f64,
+}
+
+pub struct OrderBook {
+    // One resting-order list per outcome per side, kept sorted so the best price is
+    // always at the end (cheapest to pop/truncate from a Vec).
+    bids: Vec<Vec<Order>>,
+    asks: Vec<Vec<Order>>,
+}
+
+impl OrderBook {
+    pub fn new(num_outcomes: usize) -> OrderBook {
+        OrderBook {
+            bids: vec![Vec::new(); num_outcomes],
+            asks: vec![Vec::new(); num_outcomes],
+        }
+    }
+
+    pub fn post(&mut self, order: Order) {
+        let outcome_id = order.outcome_id;
+        match order.side {
+            // Best bid = highest price, so sort ascending and pop from the end.
+            Side::Buy => {
+                let book = &mut self.bids[outcome_id];
+                book.push(order);
+                book.sort_by(|a, b| a.limit_price.partial_cmp(&b.limit_price).unwrap());
+            }
+            // Best ask = lowest price, so sort descending and pop from the end.
+            Side::Sell => {
+                let book = &mut self.asks[outcome_id];
+                book.push(order);
+                book.sort_by(|a, b| b.limit_price.partial_cmp(&a.limit_price).unwrap());
+            }
+        }
+    }
+
+    fn best(&self, outcome_id: usize, side: Side) -> Option<&Order> {
+        match side {
+            Side::Buy => self.bids[outcome_id].last(),
+            Side::Sell => self.asks[outcome_id].last(),
+        }
+    }
+
+    // Fills `shares` against the best resting order on `side`, shrinking it in place
+    // or removing it once exhausted. Returns the filled amount and the maker's address.
+    fn fill_best(&mut self, outcome_id: usize, side: Side, shares: f64) -> (f64, String) {
+        let book = match side {
+            Side::Buy => &mut self.bids[outcome_id],
+            Side::Sell => &mut self.asks[outcome_id],
+        };
+
+        let order = book.last_mut().expect("fill_best called with an empty book");
+        let address = order.address.clone();
+        let filled = shares.min(order.shares);
+        order.shares -= filled;
+
+        if order.shares <= 0.0 {
+            book.pop();
+        }
+
+        (filled, address)
+    }
+}
+
+impl Market {
+    // Routes a buy order: takes resting asks that are both within `max_price` and
+    // better than the curve's current price, then walks the LMSR curve for the
+    // remainder up to `max_price`, and rests whatever's left as a resting bid.
+    pub fn route_buy(&mut self, address: String, outcome_id: usize, shares: f64, max_price: f64) -> FillBreakdown {
+        self.route(address, outcome_id, shares, max_price, Side::Buy)
+    }
+
+    // Mirror of route_buy for the sell side: takes resting bids, then walks the curve
+    // down to `min_price`, resting any remainder as a resting ask.
+    pub fn route_sell(&mut self, address: String, outcome_id: usize, shares: f64, min_price: f64) -> FillBreakdown {
+        self.route(address, outcome_id, shares, min_price, Side::Sell)
+    }
+
+    fn route(&mut self, address: String, outcome_id: usize, shares: f64, limit_price: f64, side: Side) -> FillBreakdown {
+        let mut breakdown = FillBreakdown::default();
+        if self.resolved || shares <= 0.0 || !self.portfolios.contains_key(&address) {
+            return breakdown;
+        }
+
+        let mut remaining = shares;
+        let mut book_cost = 0.0;
+
+        // The book side that can fill us is the opposite side of our own order.
+        let counter_side = match side { Side::Buy => Side::Sell, Side::Sell => Side::Buy };
+
+        while remaining > 0.0 {
+            let curve_price = self.market_maker.price(outcome_id);
+            let resting = match self.order_book.best(outcome_id, counter_side) {
+                Some(order) => order.clone(),
+                None => break,
+            };
+            let matches = match side {
+                Side::Buy => resting.limit_price <= limit_price && resting.limit_price < curve_price,
+                Side::Sell => resting.limit_price >= limit_price && resting.limit_price > curve_price,
+            };
+            if !matches {
+                break;
+            }
+
+            let (buyer, seller) = match side {
+                Side::Buy => (address.as_str(), resting.address.as_str()),
+                Side::Sell => (resting.address.as_str(), address.as_str()),
+            };
+
+            // Bound the fill by what the buyer can actually pay and what the seller
+            // actually holds, since resting orders aren't escrowed at post time.
+            let buyer_affordable_shares = match self.portfolios.get(buyer) {
+                Some(portfolio) if resting.limit_price > 0.0 => portfolio.collateral / resting.limit_price,
+                Some(_) => f64::INFINITY,
+                None => break,
+            };
+            let seller_available_shares = match self.portfolios.get(seller) {
+                Some(portfolio) => portfolio.outcome_shares[outcome_id].max(0.0),
+                None => break,
+            };
+
+            let feasible = remaining.min(resting.shares).min(buyer_affordable_shares).min(seller_available_shares);
+            if feasible <= 0.0 {
+                break;
+            }
+
+            let (filled, _) = self.order_book.fill_best(outcome_id, counter_side, feasible);
+            self.settle_match(buyer, seller, outcome_id, filled, resting.limit_price);
+
+            remaining -= filled;
+            breakdown.book_shares += filled;
+            book_cost += filled * resting.limit_price;
+        }
+
+        if breakdown.book_shares > 0.0 {
+            breakdown.book_average_price = book_cost / breakdown.book_shares;
+        }
+
+        if remaining > 0.0 {
+            let curve_shares = match side {
+                Side::Buy => remaining.min(self.market_maker.shares_to_set_price(outcome_id, limit_price).max(0.0)),
+                Side::Sell => remaining.min((-self.market_maker.shares_to_set_price(outcome_id, limit_price)).max(0.0)),
+            };
+
+            if curve_shares > 0.0 {
+                let signed_shares = match side { Side::Buy => curve_shares, Side::Sell => -curve_shares };
+                let cost = self.market_maker.cost_to_trade(outcome_id, signed_shares);
+                let applied = match self.portfolios.get_mut(&address) {
+                    Some(portfolio) if portfolio.collateral >= cost => {
+                        portfolio.outcome_shares[outcome_id] += signed_shares;
+                        portfolio.collateral -= cost;
+                        self.market_maker.trade(outcome_id, signed_shares);
+                        true
+                    }
+                    _ => false,
+                };
+
+                if applied {
+                    remaining -= curve_shares;
+                    breakdown.curve_shares = curve_shares;
+                    breakdown.curve_average_price = cost.abs() / curve_shares;
+                }
+            }
+        }
+
+        if remaining > 0.0 {
+            self.order_book.post(Order { address, outcome_id, shares: remaining, limit_price, side });
+        }
+
+        breakdown
+    }
+
+    // Transfers `shares` of `outcome_id` from `seller` to `buyer`, and their collateral
+    // value at `price` from `buyer` to `seller`. Callers must have already verified the buyer can cover the
+    // collateral and the seller holds the shares; this only moves the balances.
+    fn settle_match(&mut self, buyer: &str, seller: &str, outcome_id: usize, shares: f64, price: f64) {
+        let collateral_value = shares * price;
+
+        {
+            let buyer_portfolio = self.portfolios.get_mut(buyer).unwrap();
+            buyer_portfolio.outcome_shares[outcome_id] += shares;
+            buyer_portfolio.collateral -= collateral_value;
+        }
+        {
+            let seller_portfolio = self.portfolios.get_mut(seller).unwrap();
+            seller_portfolio.outcome_shares[outcome_id] -= shares;
+            seller_portfolio.collateral += collateral_value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Market;
+
+    fn assert_within_epsilon(x: f64, y: f64) {
+        assert!((x - y).abs() < 0.0001, "{} and {} aren't within epsilon", x, y);
+    }
+
+    #[test]
+    fn route_buy_matches_resting_ask_before_the_curve() {
+        let mut market = Market::new(100.0, 2);
+        let maker = "maker";
+        let taker = "taker";
+
+        market.add_collateral(String::from(maker), 1000.0);
+        market.trade(String::from(maker), 0, 20.0);
+        market.post_limit_order(Order {
+            address: String::from(maker),
+            outcome_id: 0,
+            shares: 5.0,
+            limit_price: 0.3,
+            side: Side::Sell,
+        });
+
+        market.add_collateral(String::from(taker), 1000.0);
+        let breakdown = market.route_buy(String::from(taker), 0, 5.0, 0.9);
+
+        assert_within_epsilon(breakdown.book_shares, 5.0);
+        assert_within_epsilon(breakdown.book_average_price, 0.3);
+        assert_eq!(breakdown.curve_shares, 0.0);
+
+        let taker_portfolio = &market.portfolios[&String::from(taker)];
+        assert_within_epsilon(taker_portfolio.outcome_shares[0], 5.0);
+        assert_within_epsilon(taker_portfolio.collateral, 1000.0 - 5.0 * 0.3);
+
+        let maker_portfolio = &market.portfolios[&String::from(maker)];
+        assert_within_epsilon(maker_portfolio.outcome_shares[0], 20.0 - 5.0);
+    }
+
+    #[test]
+    fn route_buy_caps_book_fill_at_buyers_collateral() {
+        let mut market = Market::new(100.0, 2);
+        let maker = "maker";
+        let taker = "taker";
+
+        market.add_collateral(String::from(maker), 1000.0);
+        market.trade(String::from(maker), 0, 100.0);
+        market.post_limit_order(Order {
+            address: String::from(maker),
+            outcome_id: 0,
+            shares: 100.0,
+            limit_price: 0.5,
+            side: Side::Sell,
+        });
+
+        // Only enough collateral to afford 2 shares at 0.5 each.
+        market.add_collateral(String::from(taker), 1.0);
+        let breakdown = market.route_buy(String::from(taker), 0, 100.0, 0.9);
+
+        assert_within_epsilon(breakdown.book_shares, 2.0);
+
+        let taker_portfolio = &market.portfolios[&String::from(taker)];
+        assert!(taker_portfolio.collateral >= -0.0001);
+        assert_within_epsilon(taker_portfolio.outcome_shares[0], 2.0);
+    }
+
+    #[test]
+    fn route_buy_caps_book_fill_at_sellers_shares() {
+        let mut market = Market::new(100.0, 2);
+        let maker = "maker";
+        let taker = "taker";
+
+        // Maker posts an ask without holding any shares of the outcome (no escrow at
+        // post time), so the book can't fill against it at all.
+        market.add_collateral(String::from(maker), 1000.0);
+        market.post_limit_order(Order {
+            address: String::from(maker),
+            outcome_id: 0,
+            shares: 50.0,
+            limit_price: 0.2,
+            side: Side::Sell,
+        });
+
+        market.add_collateral(String::from(taker), 1000.0);
+        let breakdown = market.route_buy(String::from(taker), 0, 10.0, 0.9);
+
+        assert_eq!(breakdown.book_shares, 0.0);
+        assert!(breakdown.curve_shares > 0.0);
+
+        let maker_portfolio = &market.portfolios[&String::from(maker)];
+        assert_eq!(maker_portfolio.outcome_shares[0], 0.0);
+    }
+
+    #[test]
+    fn route_buy_falls_back_to_curve_when_book_is_empty() {
+        let mut market = Market::new(100.0, 2);
+        let taker = "taker";
+
+        market.add_collateral(String::from(taker), 1000.0);
+        let breakdown = market.route_buy(String::from(taker), 0, 10.0, 0.9);
+
+        assert_eq!(breakdown.book_shares, 0.0);
+        assert!(breakdown.curve_shares > 0.0);
+
+        let taker_portfolio = &market.portfolios[&String::from(taker)];
+        assert_within_epsilon(taker_portfolio.outcome_shares[0], breakdown.curve_shares);
+    }
+
+    #[test]
+    fn route_buy_rests_unmatched_remainder() {
+        let mut market = Market::new(100.0, 2);
+        let taker = "taker";
+
+        market.add_collateral(String::from(taker), 1.0);
+        let breakdown = market.route_buy(String::from(taker), 0, 1000.0, 0.5);
+
+        let remaining = 1000.0 - breakdown.book_shares - breakdown.curve_shares;
+        assert!(remaining > 0.0);
+        assert_eq!(market.order_book.best(0, Side::Buy).unwrap().shares, remaining);
+    }
+}